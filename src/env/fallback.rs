@@ -1,12 +1,195 @@
 // Copyright (c) 2017-present, PingCAP, Inc. Licensed under Apache-2.0.
 
-use std::io::{Read, Result as IoResult, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::fmt;
 use std::fs::File;
+use std::io::{
+    Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write,
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use sha2::{Digest, Sha256};
+
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
 
 use crate::env::{FileSystem, Handle, Permission, WriteExt};
 
+/// Thin wrapper around the `LockFileEx`/`UnlockFile` Win32 calls used to
+/// take an advisory whole-file lock, mirroring `flock` on Unix.
+#[cfg(windows)]
+mod windows_lock {
+    use std::fs::File;
+    use std::io::{Error as IoError, Result as IoResult};
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{LockFileEx, UnlockFile};
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    pub(super) use windows_sys::Win32::Storage::FileSystem::{
+        LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+    };
+
+    pub(super) fn lock_file(file: &File, flags: u32) -> IoResult<()> {
+        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+        let ret = unsafe {
+            LockFileEx(
+                file.as_raw_handle() as _,
+                flags,
+                0,
+                u32::MAX,
+                u32::MAX,
+                &mut overlapped,
+            )
+        };
+        if ret != 0 {
+            Ok(())
+        } else {
+            Err(IoError::last_os_error())
+        }
+    }
+
+    pub(super) fn unlock_file(file: &File) -> IoResult<()> {
+        let ret = unsafe { UnlockFile(file.as_raw_handle() as _, 0, 0, u32::MAX, u32::MAX) };
+        if ret != 0 {
+            Ok(())
+        } else {
+            Err(IoError::last_os_error())
+        }
+    }
+}
+
+/// Selects how [`LogFd::allocate`] should reserve or reclaim space in a
+/// file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallocMode {
+    /// Reserves `size` bytes starting at `offset` without growing the
+    /// file's reported length.
+    ///
+    /// Only supported where native `fallocate(2)` with
+    /// `FALLOC_FL_KEEP_SIZE` is available (Linux). On other Unix targets,
+    /// or if the kernel reports `fallocate(2)` as unsupported
+    /// (`EOPNOTSUPP` or `ENOSYS`), there is no equivalent of
+    /// this flag for `posix_fallocate`, so [`LogFd::allocate`] returns an
+    /// [`Unsupported`](IoErrorKind::Unsupported) error rather than
+    /// silently growing the file.
+    PreallocateKeepSize,
+    /// Reserves `size` bytes starting at `offset`, growing the file's
+    /// length if the range extends past the current end.
+    PreallocateExtendSize,
+    /// Deallocates the byte range, turning it into a hole that reads back
+    /// as zeroes, without changing the file's reported length.
+    PunchHole,
+    /// Zeroes the byte range in place, which the filesystem may implement
+    /// by turning it into a hole.
+    ZeroRange,
+}
+
+/// The operation that was being attempted when a wrapped I/O error
+/// occurred, used purely to annotate error messages with context.
+#[derive(Debug, Clone, Copy)]
+enum ErrorKind {
+    Open,
+    Create,
+    Truncate,
+    Allocate,
+    Sync,
+    Read,
+    Write,
+    Seek,
+    Metadata,
+    Delete,
+    Rename,
+    Lock,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Open => "open",
+            ErrorKind::Create => "create",
+            ErrorKind::Truncate => "truncate",
+            ErrorKind::Allocate => "allocate",
+            ErrorKind::Sync => "sync",
+            ErrorKind::Read => "read",
+            ErrorKind::Write => "write",
+            ErrorKind::Seek => "seek",
+            ErrorKind::Metadata => "read metadata of",
+            ErrorKind::Delete => "delete",
+            ErrorKind::Rename => "rename",
+            ErrorKind::Lock => "lock",
+        })
+    }
+}
+
+/// An [`io::Error`](IoError) annotated with the operation and path(s) that
+/// produced it, in the spirit of the `fs-err` crate.
+#[derive(Debug)]
+struct PathError {
+    kind: ErrorKind,
+    path: PathBuf,
+    to: Option<PathBuf>,
+    source: IoError,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.to {
+            Some(to) => write!(
+                f,
+                "failed to {} {:?} to {:?}: {}",
+                self.kind, self.path, to, self.source
+            ),
+            None => write!(
+                f,
+                "failed to {} {:?}: {}",
+                self.kind, self.path, self.source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Wraps `source` with the operation `kind` and the offending `path`,
+/// preserving `source`'s [`IoErrorKind`] so callers can still match on it.
+fn wrap_err(kind: ErrorKind, path: impl AsRef<Path>, source: IoError) -> IoError {
+    let io_kind = source.kind();
+    IoError::new(
+        io_kind,
+        PathError {
+            kind,
+            path: path.as_ref().to_path_buf(),
+            to: None,
+            source,
+        },
+    )
+}
+
+/// Like [`wrap_err`], but for operations involving a source and destination
+/// path, e.g. a rename.
+fn wrap_err_two(
+    kind: ErrorKind,
+    from: impl AsRef<Path>,
+    to: impl AsRef<Path>,
+    source: IoError,
+) -> IoError {
+    let io_kind = source.kind();
+    IoError::new(
+        io_kind,
+        PathError {
+            kind,
+            path: from.as_ref().to_path_buf(),
+            to: Some(to.as_ref().to_path_buf()),
+            source,
+        },
+    )
+}
 
 /// A RAII-style low-level file. Errors occurred during automatic resource
 /// release are logged and ignored.
@@ -15,44 +198,321 @@ use crate::env::{FileSystem, Handle, Permission, WriteExt};
 /// supported on *Unix*, and primarily optimized for *Linux*.
 ///
 /// All [`LogFd`] instances are opened with read and write permission.
-pub struct LogFd(File);
+pub struct LogFd {
+    file: File,
+    path: PathBuf,
+    locked: std::sync::atomic::AtomicBool,
+}
 
 impl LogFd {
     /// Opens a file with the given `path`.
     pub fn open<P: AsRef<Path>>(path: P, perm: Permission) -> IoResult<Self> {
+        let path = path.as_ref().to_path_buf();
         File::options()
             .read(true)
             .write(perm == Permission::ReadWrite)
-            .open(path)
-            .map(|file| Self(file))
+            .open(&path)
+            .map(|file| Self {
+                file,
+                path: path.clone(),
+                locked: std::sync::atomic::AtomicBool::new(false),
+            })
+            .map_err(|e| wrap_err(ErrorKind::Open, &path, e))
     }
 
     /// Opens a file with the given `path`. The specified file will be created
     /// first if not exists.
     pub fn create<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        let path = path.as_ref().to_path_buf();
         File::options()
             .read(true)
             .write(true)
             .create(true)
-            .open(path)
-            .map(|file| Self(file))
+            .open(&path)
+            .map(|file| Self {
+                file,
+                path: path.clone(),
+                locked: std::sync::atomic::AtomicBool::new(false),
+            })
+            .map_err(|e| wrap_err(ErrorKind::Create, &path, e))
+    }
+
+    /// Returns the path this file was opened or created with.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     /// Truncates all data after `offset`.
     pub fn truncate(&self, offset: usize) -> IoResult<()> {
-        self.0.set_len(offset as u64)
+        self.file
+            .set_len(offset as u64)
+            .map_err(|e| wrap_err(ErrorKind::Truncate, &self.path, e))
+    }
+
+    /// Reserves or reclaims `size` bytes starting at `offset` according to
+    /// `mode`. Uses `fallocate(2)` on Linux, falling back to
+    /// `posix_fallocate` for plain preallocation and to a manual zero-write
+    /// loop where neither syscall is available.
+    #[cfg(target_os = "linux")]
+    pub fn allocate(&self, offset: usize, size: usize, mode: FallocMode) -> IoResult<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let flags = match mode {
+            FallocMode::PreallocateKeepSize => libc::FALLOC_FL_KEEP_SIZE,
+            FallocMode::PreallocateExtendSize => 0,
+            FallocMode::PunchHole => libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            FallocMode::ZeroRange => libc::FALLOC_FL_ZERO_RANGE,
+        };
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                flags,
+                offset as libc::off_t,
+                size as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = IoError::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => {
+                self.allocate_fallback(offset, size, mode)
+            }
+            _ => Err(wrap_err(ErrorKind::Allocate, &self.path, err)),
+        }
+    }
+
+    /// Reserves or reclaims `size` bytes starting at `offset` according to
+    /// `mode`, via `posix_fallocate` for plain preallocation and a manual
+    /// zero-write loop for punching holes or zeroing a range.
+    ///
+    /// `FallocMode::PreallocateKeepSize` is not supported on this path; see
+    /// its documentation.
+    #[cfg(all(unix, not(target_os = "linux")))]
+    pub fn allocate(&self, offset: usize, size: usize, mode: FallocMode) -> IoResult<()> {
+        self.allocate_fallback(offset, size, mode)
+    }
+
+    #[cfg(unix)]
+    fn allocate_fallback(&self, offset: usize, size: usize, mode: FallocMode) -> IoResult<()> {
+        match mode {
+            // `posix_fallocate` has no equivalent of `FALLOC_FL_KEEP_SIZE`:
+            // it always grows the file to `offset + size` if needed. Rather
+            // than silently extend the file's reported length against
+            // `PreallocateKeepSize`'s documented contract, reject it here so
+            // callers relying on that guarantee see a clear error instead
+            // of corrupted assumptions about the file's size.
+            FallocMode::PreallocateKeepSize => Err(wrap_err(
+                ErrorKind::Allocate,
+                &self.path,
+                IoError::new(
+                    IoErrorKind::Unsupported,
+                    "keep-size preallocation requires native fallocate(2); \
+                     posix_fallocate always grows the file",
+                ),
+            )),
+            FallocMode::PreallocateExtendSize => {
+                use std::os::unix::io::AsRawFd;
+                let ret = unsafe {
+                    libc::posix_fallocate(
+                        self.file.as_raw_fd(),
+                        offset as libc::off_t,
+                        size as libc::off_t,
+                    )
+                };
+                if ret == 0 {
+                    Ok(())
+                } else {
+                    Err(wrap_err(
+                        ErrorKind::Allocate,
+                        &self.path,
+                        IoError::from_raw_os_error(ret),
+                    ))
+                }
+            }
+            FallocMode::PunchHole | FallocMode::ZeroRange => self.zero_range(offset, size),
+        }
     }
 
-    pub fn allocate(&self, _offset: usize, _size: usize) -> IoResult<()> {
+    /// Overwrites `size` bytes starting at `offset` with zeroes. Used as a
+    /// last-resort fallback when neither `fallocate` nor `posix_fallocate`
+    /// is available for the requested mode.
+    fn zero_range(&self, offset: usize, size: usize) -> IoResult<()> {
+        const ZEROS: [u8; 4096] = [0; 4096];
+        let mut pos = offset;
+        let mut remaining = size;
+        while remaining > 0 {
+            let chunk = remaining.min(ZEROS.len());
+            let n = self.write_at(&ZEROS[..chunk], pos)?;
+            if n == 0 {
+                return Err(wrap_err(
+                    ErrorKind::Allocate,
+                    &self.path,
+                    IoError::new(IoErrorKind::WriteZero, "failed to zero-fill file range"),
+                ));
+            }
+            pos += n;
+            remaining -= n;
+        }
         Ok(())
     }
 
     pub fn size(&self) -> IoResult<usize> {
-        self.0.metadata().map(|meta| meta.len() as usize)
+        self.file
+            .metadata()
+            .map(|meta| meta.len() as usize)
+            .map_err(|e| wrap_err(ErrorKind::Metadata, &self.path, e))
     }
 
     pub fn sync(&self) -> IoResult<()> {
-        self.0.sync_data()
+        self.file
+            .sync_data()
+            .map_err(|e| wrap_err(ErrorKind::Sync, &self.path, e))
+    }
+
+    /// Takes an advisory exclusive lock on the whole file, blocking until
+    /// it becomes available. Used to keep a second engine instance from
+    /// opening the same data directory.
+    #[cfg(unix)]
+    pub fn lock_exclusive(&self) -> IoResult<()> {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_EX) };
+        if ret == 0 {
+            self.locked
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        } else {
+            Err(wrap_err(
+                ErrorKind::Lock,
+                &self.path,
+                IoError::last_os_error(),
+            ))
+        }
+    }
+
+    /// Takes an advisory exclusive lock on the whole file, returning
+    /// immediately with a [`WouldBlock`](IoErrorKind::WouldBlock) error if
+    /// another handle already holds it.
+    #[cfg(unix)]
+    pub fn try_lock_exclusive(&self) -> IoResult<()> {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret == 0 {
+            self.locked
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        } else {
+            Err(wrap_err(
+                ErrorKind::Lock,
+                &self.path,
+                IoError::last_os_error(),
+            ))
+        }
+    }
+
+    /// Releases a lock previously taken by [`LogFd::lock_exclusive`] or
+    /// [`LogFd::try_lock_exclusive`].
+    #[cfg(unix)]
+    pub fn unlock(&self) -> IoResult<()> {
+        use std::os::unix::io::AsRawFd;
+        let ret = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        if ret == 0 {
+            self.locked
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        } else {
+            Err(wrap_err(
+                ErrorKind::Lock,
+                &self.path,
+                IoError::last_os_error(),
+            ))
+        }
+    }
+
+    /// Takes an advisory exclusive lock on the whole file, blocking until
+    /// it becomes available. Used to keep a second engine instance from
+    /// opening the same data directory.
+    #[cfg(windows)]
+    pub fn lock_exclusive(&self) -> IoResult<()> {
+        windows_lock::lock_file(&self.file, windows_lock::LOCKFILE_EXCLUSIVE_LOCK)
+            .map_err(|e| wrap_err(ErrorKind::Lock, &self.path, e))?;
+        self.locked
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Takes an advisory exclusive lock on the whole file, returning
+    /// immediately with a [`WouldBlock`](IoErrorKind::WouldBlock) error if
+    /// another handle already holds it.
+    #[cfg(windows)]
+    pub fn try_lock_exclusive(&self) -> IoResult<()> {
+        windows_lock::lock_file(
+            &self.file,
+            windows_lock::LOCKFILE_EXCLUSIVE_LOCK | windows_lock::LOCKFILE_FAIL_IMMEDIATELY,
+        )
+        .map_err(|e| wrap_err(ErrorKind::Lock, &self.path, e))?;
+        self.locked
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Releases a lock previously taken by [`LogFd::lock_exclusive`] or
+    /// [`LogFd::try_lock_exclusive`].
+    #[cfg(windows)]
+    pub fn unlock(&self) -> IoResult<()> {
+        windows_lock::unlock_file(&self.file)
+            .map_err(|e| wrap_err(ErrorKind::Lock, &self.path, e))?;
+        self.locked
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Reads bytes starting at `offset` via `pread`/`seek_read`, without
+    /// touching the file's shared kernel cursor.
+    #[cfg(unix)]
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> IoResult<usize> {
+        self.file
+            .read_at(buf, offset as u64)
+            .map_err(|e| wrap_err(ErrorKind::Read, &self.path, e))
+    }
+
+    /// Reads bytes starting at `offset` via `pread`/`seek_read`, without
+    /// touching the file's shared kernel cursor.
+    #[cfg(windows)]
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> IoResult<usize> {
+        self.file
+            .seek_read(buf, offset as u64)
+            .map_err(|e| wrap_err(ErrorKind::Read, &self.path, e))
+    }
+
+    /// Writes bytes starting at `offset` via `pwrite`/`seek_write`, without
+    /// touching the file's shared kernel cursor.
+    #[cfg(unix)]
+    pub fn write_at(&self, buf: &[u8], offset: usize) -> IoResult<usize> {
+        self.file
+            .write_at(buf, offset as u64)
+            .map_err(|e| wrap_err(ErrorKind::Write, &self.path, e))
+    }
+
+    /// Writes bytes starting at `offset` via `pwrite`/`seek_write`, without
+    /// touching the file's shared kernel cursor.
+    #[cfg(windows)]
+    pub fn write_at(&self, buf: &[u8], offset: usize) -> IoResult<usize> {
+        self.file
+            .seek_write(buf, offset as u64)
+            .map_err(|e| wrap_err(ErrorKind::Write, &self.path, e))
+    }
+}
+
+impl Drop for LogFd {
+    fn drop(&mut self) {
+        if !self.locked.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = self.unlock() {
+            log::warn!("failed to release lock on {:?}: {}", self.path, e);
+        }
     }
 }
 
@@ -68,6 +528,18 @@ impl Handle for LogFd {
     fn sync(&self) -> IoResult<()> {
         self.sync()
     }
+
+    fn lock_exclusive(&self) -> IoResult<()> {
+        self.lock_exclusive()
+    }
+
+    fn try_lock_exclusive(&self) -> IoResult<()> {
+        self.try_lock_exclusive()
+    }
+
+    fn unlock(&self) -> IoResult<()> {
+        self.unlock()
+    }
 }
 
 /// A low-level file adapted for standard interfaces including [`Seek`],
@@ -75,46 +547,100 @@ impl Handle for LogFd {
 pub struct LogFile {
     inner: Arc<RwLock<LogFd>>,
     offset: usize,
+    path: PathBuf,
 }
 
 impl LogFile {
     /// Creates a new [`LogFile`] from a shared [`LogFd`].
     pub fn new(fd: Arc<LogFd>) -> Self {
         let fd = unsafe { Arc::into_raw(fd).read() };
+        let path = fd.path.clone();
         Self {
             inner: Arc::new(RwLock::new(fd)),
             offset: 0,
+            path,
         }
     }
 
-    fn inner(&self) -> std::sync::RwLockReadGuard<'_, LogFd> {
-        self.inner.read().unwrap()
+    /// Returns the path of the underlying file.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    fn inner_mut(&self) -> std::sync::RwLockWriteGuard<'_, LogFd> {
-        self.inner.write().unwrap()
+    fn inner(&self) -> std::sync::RwLockReadGuard<'_, LogFd> {
+        self.inner.read().unwrap()
     }
 }
 
 impl Write for LogFile {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.inner_mut().0.write(buf)
+        let mut written = 0;
+        while written < buf.len() {
+            let result = self
+                .inner()
+                .write_at(&buf[written..], self.offset + written);
+            let n = match result {
+                Ok(n) => n,
+                Err(e) => {
+                    self.offset += written;
+                    return Err(e);
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            written += n;
+        }
+        self.offset += written;
+        Ok(written)
     }
 
     fn flush(&mut self) -> IoResult<()> {
-        self.inner_mut().0.flush()
+        Ok(())
     }
 }
 
 impl Read for LogFile {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        self.inner_mut().0.read(buf)
+        let mut read = 0;
+        while read < buf.len() {
+            let result = self.inner().read_at(&mut buf[read..], self.offset + read);
+            let n = match result {
+                Ok(n) => n,
+                Err(e) => {
+                    self.offset += read;
+                    return Err(e);
+                }
+            };
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        self.offset += read;
+        Ok(read)
     }
 }
 
 impl Seek for LogFile {
     fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
-        self.inner_mut().0.seek(pos)
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.inner().size()? as i64 + offset,
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+        };
+        if new_offset < 0 {
+            return Err(wrap_err(
+                ErrorKind::Seek,
+                &self.path,
+                IoError::new(
+                    IoErrorKind::InvalidInput,
+                    "invalid seek to a negative position",
+                ),
+            ));
+        }
+        self.offset = new_offset as usize;
+        Ok(self.offset as u64)
     }
 }
 
@@ -125,8 +651,8 @@ impl WriteExt for LogFile {
         Ok(())
     }
 
-    fn allocate(&mut self, offset: usize, size: usize) -> IoResult<()> {
-        self.inner().allocate(offset, size)
+    fn allocate(&mut self, offset: usize, size: usize, mode: FallocMode) -> IoResult<()> {
+        self.inner().allocate(offset, size, mode)
     }
 }
 
@@ -146,11 +672,12 @@ impl FileSystem for DefaultFileSystem {
     }
 
     fn delete<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        std::fs::remove_file(path)
+        std::fs::remove_file(&path).map_err(|e| wrap_err(ErrorKind::Delete, &path, e))
     }
 
     fn rename<P: AsRef<Path>>(&self, src_path: P, dst_path: P) -> IoResult<()> {
-        std::fs::rename(src_path, dst_path)
+        std::fs::rename(&src_path, &dst_path)
+            .map_err(|e| wrap_err_two(ErrorKind::Rename, &src_path, &dst_path, e))
     }
 
     fn new_reader(&self, handle: Arc<Self::Handle>) -> IoResult<Self::Reader> {
@@ -161,3 +688,909 @@ impl FileSystem for DefaultFileSystem {
         Ok(LogFile::new(handle))
     }
 }
+
+/// Hash algorithm used to build a file's integrity [`MerkleTree`]. Recorded
+/// in the sidecar header so a file can't silently be re-verified with a
+/// different algorithm than it was built with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HashAlgorithm {
+    Sha256 = 0,
+}
+
+impl HashAlgorithm {
+    fn from_u8(v: u8) -> IoResult<Self> {
+        match v {
+            0 => Ok(HashAlgorithm::Sha256),
+            _ => Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "unknown integrity hash algorithm",
+            )),
+        }
+    }
+
+    fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    fn hash(self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                for part in parts {
+                    hasher.update(part);
+                }
+                hasher.finalize().to_vec()
+            }
+        }
+    }
+}
+
+/// A sibling digest collected while walking from a leaf to the root of a
+/// [`MerkleTree`]. `None` means the leaf had no sibling at that level (an
+/// odd node carried forward by re-hashing itself alone).
+type MerkleProof = Vec<Option<Vec<u8>>>;
+
+/// A Merkle hash tree over a file's fixed-size blocks, stored compactly as
+/// a flat array of level-ordered digests (leaves first, root last).
+///
+/// Modeled on fs-verity: every data block is hashed into a leaf, interior
+/// nodes hash their children's digests, and only the root needs to be
+/// trusted. Verifying a single block only requires the sibling digests
+/// along its root-to-leaf path, not a rescan of the whole file.
+#[derive(Debug, Clone)]
+struct MerkleTree {
+    algorithm: HashAlgorithm,
+    /// `levels[0]` holds the concatenated leaf digests, `levels.last()` is
+    /// the single-digest root.
+    levels: Vec<Vec<u8>>,
+}
+
+impl MerkleTree {
+    fn build(leaves: &[Vec<u8>], algorithm: HashAlgorithm) -> Self {
+        let mut current = if leaves.is_empty() {
+            vec![algorithm.hash(&[&[]])]
+        } else {
+            leaves.to_vec()
+        };
+        let mut levels = vec![current.concat()];
+        while current.len() > 1 {
+            let mut next = Vec::with_capacity(current.len().div_ceil(2));
+            for pair in current.chunks(2) {
+                next.push(match pair {
+                    [a, b] => algorithm.hash(&[a, b]),
+                    [a] => algorithm.hash(&[a]),
+                    _ => unreachable!(),
+                });
+            }
+            levels.push(next.concat());
+            current = next;
+        }
+        Self { algorithm, levels }
+    }
+
+    /// Reconstructs a tree from its on-disk flat digest array, without
+    /// recomputing any hash.
+    fn from_levels(algorithm: HashAlgorithm, levels: Vec<Vec<u8>>) -> Self {
+        Self { algorithm, levels }
+    }
+
+    fn root(&self) -> &[u8] {
+        self.levels.last().unwrap()
+    }
+
+    fn leaf_count(&self) -> usize {
+        self.levels[0].len() / self.algorithm.digest_len()
+    }
+
+    fn count_at(&self, level: usize) -> usize {
+        self.levels[level].len() / self.algorithm.digest_len()
+    }
+
+    fn digest_at(&self, level: usize, index: usize) -> &[u8] {
+        let len = self.algorithm.digest_len();
+        &self.levels[level][index * len..(index + 1) * len]
+    }
+
+    /// Returns the sibling digests from leaf `index` up to (but excluding)
+    /// the root, innermost first.
+    fn proof(&self, index: usize) -> MerkleProof {
+        let mut index = index;
+        let mut proof = Vec::with_capacity(self.levels.len() - 1);
+        for level in 0..self.levels.len() - 1 {
+            let sibling = index ^ 1;
+            proof.push(
+                (sibling < self.count_at(level)).then(|| self.digest_at(level, sibling).to_vec()),
+            );
+            index /= 2;
+        }
+        proof
+    }
+
+    /// Recomputes the path from `leaf_hash` at `index` up through `proof`
+    /// and compares the result against `root`.
+    fn verify(
+        algorithm: HashAlgorithm,
+        leaf_hash: &[u8],
+        index: usize,
+        proof: &MerkleProof,
+        root: &[u8],
+    ) -> bool {
+        let mut index = index;
+        let mut current = leaf_hash.to_vec();
+        for sibling in proof {
+            current = match sibling {
+                Some(s) if index % 2 == 0 => algorithm.hash(&[&current, s]),
+                Some(s) => algorithm.hash(&[s, &current]),
+                None => algorithm.hash(&[&current]),
+            };
+            index /= 2;
+        }
+        current == root
+    }
+}
+
+/// Error identifying the file and block offset that failed integrity
+/// verification, so recovery can decide whether to truncate there.
+#[derive(Debug)]
+struct IntegrityError {
+    path: PathBuf,
+    block_offset: u64,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "integrity check failed for {:?} at block offset {}",
+            self.path, self.block_offset
+        )
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+fn integrity_error(path: &Path, block_offset: u64) -> IoError {
+    IoError::new(
+        IoErrorKind::InvalidData,
+        IntegrityError {
+            path: path.to_path_buf(),
+            block_offset,
+        },
+    )
+}
+
+/// Error raised when a sidecar's recorded block size or hash algorithm
+/// doesn't match the [`VerifiedFileSystem`] opening it, so a config change
+/// is rejected instead of silently misverifying.
+#[derive(Debug)]
+struct SidecarConfigError {
+    path: PathBuf,
+    reason: &'static str,
+}
+
+impl fmt::Display for SidecarConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mismatched integrity sidecar for {:?}: {}",
+            self.path, self.reason
+        )
+    }
+}
+
+impl std::error::Error for SidecarConfigError {}
+
+fn sidecar_config_error(path: &Path, reason: &'static str) -> IoError {
+    IoError::new(
+        IoErrorKind::InvalidData,
+        SidecarConfigError {
+            path: path.to_path_buf(),
+            reason,
+        },
+    )
+}
+
+const SIDECAR_MAGIC: &[u8; 4] = b"REMT";
+const SIDECAR_VERSION: u8 = 1;
+/// Default block size for [`VerifiedFileSystem::with_defaults`].
+const DEFAULT_INTEGRITY_BLOCK_SIZE: usize = 4096;
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".mtree");
+    PathBuf::from(name)
+}
+
+fn encode_sidecar(block_size: usize, tree: &MerkleTree) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(SIDECAR_MAGIC);
+    buf.push(SIDECAR_VERSION);
+    buf.push(tree.algorithm as u8);
+    buf.extend_from_slice(&(block_size as u32).to_le_bytes());
+    buf.extend_from_slice(&(tree.leaf_count() as u64).to_le_bytes());
+    for level in &tree.levels {
+        buf.extend_from_slice(level);
+    }
+    buf
+}
+
+fn decode_sidecar(
+    buf: &[u8],
+    path: &Path,
+    block_size: usize,
+    algorithm: HashAlgorithm,
+) -> IoResult<MerkleTree> {
+    if buf.len() < 4 + 1 + 1 + 4 + 8 || &buf[..4] != SIDECAR_MAGIC {
+        return Err(sidecar_config_error(
+            path,
+            "missing or corrupt sidecar header",
+        ));
+    }
+    let version = buf[4];
+    if version != SIDECAR_VERSION {
+        return Err(sidecar_config_error(path, "unsupported sidecar version"));
+    }
+    let found_algorithm = HashAlgorithm::from_u8(buf[5])?;
+    if found_algorithm != algorithm {
+        return Err(sidecar_config_error(
+            path,
+            "hash algorithm does not match configured value",
+        ));
+    }
+    let found_block_size = u32::from_le_bytes(buf[6..10].try_into().unwrap()) as usize;
+    if found_block_size != block_size {
+        return Err(sidecar_config_error(
+            path,
+            "block size does not match configured value",
+        ));
+    }
+    let leaf_count = u64::from_le_bytes(buf[10..18].try_into().unwrap()) as usize;
+
+    let digest_len = algorithm.digest_len();
+    let mut levels = Vec::new();
+    let mut offset = 18;
+    let mut count = leaf_count.max(1);
+    loop {
+        let bytes = count * digest_len;
+        if offset + bytes > buf.len() {
+            return Err(sidecar_config_error(path, "truncated sidecar tree data"));
+        }
+        levels.push(buf[offset..offset + bytes].to_vec());
+        offset += bytes;
+        if count == 1 {
+            break;
+        }
+        count = count.div_ceil(2);
+    }
+    Ok(MerkleTree::from_levels(algorithm, levels))
+}
+
+/// Shared integrity bookkeeping for one file: the block size and algorithm
+/// it was configured with, the per-block leaf hashes recorded so far, and
+/// the tree built from them as of the last [`VerifiedHandle::sync`].
+struct MerkleState {
+    block_size: usize,
+    algorithm: HashAlgorithm,
+    leaves: Vec<Vec<u8>>,
+    tree: MerkleTree,
+    dirty: bool,
+}
+
+impl MerkleState {
+    fn empty(block_size: usize, algorithm: HashAlgorithm) -> Self {
+        Self {
+            block_size,
+            algorithm,
+            leaves: Vec::new(),
+            tree: MerkleTree::build(&[], algorithm),
+            dirty: false,
+        }
+    }
+
+    fn loaded(block_size: usize, tree: MerkleTree) -> Self {
+        let algorithm = tree.algorithm;
+        let leaves = (0..tree.leaf_count())
+            .map(|i| tree.digest_at(0, i).to_vec())
+            .collect();
+        Self {
+            block_size,
+            algorithm,
+            leaves,
+            tree,
+            dirty: false,
+        }
+    }
+
+    /// Records the hash of the block at `index`, growing `leaves` if this
+    /// is the first time it's been written. Any indices skipped over (e.g.
+    /// by a `FallocMode::PreallocateExtendSize` hole followed by an
+    /// out-of-order write) are filled with the hash of a zeroed block, since
+    /// that's what a sparse hole reads back as.
+    fn record_block(&mut self, index: usize, data: &[u8]) {
+        let hash = self.algorithm.hash(&[data]);
+        match index.cmp(&self.leaves.len()) {
+            std::cmp::Ordering::Less => self.leaves[index] = hash,
+            std::cmp::Ordering::Equal => self.leaves.push(hash),
+            std::cmp::Ordering::Greater => {
+                let zero_block = vec![0u8; self.block_size];
+                let zero_hash = self.algorithm.hash(&[&zero_block]);
+                self.leaves.resize(index, zero_hash);
+                self.leaves.push(hash);
+            }
+        }
+        self.dirty = true;
+    }
+
+    fn truncate_leaves(&mut self, offset: usize) {
+        let keep = offset.div_ceil(self.block_size);
+        if keep < self.leaves.len() {
+            self.leaves.truncate(keep);
+            self.dirty = true;
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.tree = MerkleTree::build(&self.leaves, self.algorithm);
+    }
+}
+
+fn load_merkle_state<F: FileSystem>(
+    fs: &F,
+    path: &Path,
+    block_size: usize,
+    algorithm: HashAlgorithm,
+) -> IoResult<MerkleState>
+where
+    F::Reader: Read,
+{
+    let sidecar = sidecar_path(path);
+    match fs.open(&sidecar, Permission::ReadOnly) {
+        Ok(handle) => {
+            let mut reader = fs.new_reader(Arc::new(handle))?;
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf)?;
+            let tree = decode_sidecar(&buf, path, block_size, algorithm)?;
+            Ok(MerkleState::loaded(block_size, tree))
+        }
+        Err(e) if e.kind() == IoErrorKind::NotFound => {
+            Ok(MerkleState::empty(block_size, algorithm))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn store_merkle_state<F: FileSystem>(fs: &F, path: &Path, state: &MerkleState) -> IoResult<()>
+where
+    F::Writer: Write,
+{
+    let sidecar = sidecar_path(path);
+    let handle = Arc::new(fs.create(&sidecar)?);
+    let mut writer = fs.new_writer(handle.clone())?;
+    writer.write_all(&encode_sidecar(state.block_size, &state.tree))?;
+    writer.flush()?;
+    handle.sync()
+}
+
+/// [`FileSystem`] decorator that verifies every block read against a
+/// persisted Merkle hash tree, catching silent bit-rot or partial writes
+/// that would otherwise only surface as an opaque parse failure during
+/// recovery. Opt in by wrapping an existing [`FileSystem`], e.g.
+/// [`DefaultFileSystem`].
+pub struct VerifiedFileSystem<F> {
+    inner: Arc<F>,
+    block_size: usize,
+    algorithm: HashAlgorithm,
+}
+
+impl<F: FileSystem> VerifiedFileSystem<F> {
+    /// Wraps `inner`, hashing files in `block_size`-byte blocks with
+    /// `algorithm`. `block_size` and `algorithm` are recorded in each
+    /// file's sidecar and checked on every subsequent open.
+    pub fn new(inner: F, block_size: usize, algorithm: HashAlgorithm) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            block_size,
+            algorithm,
+        }
+    }
+
+    /// Wraps `inner` with the default 4 KiB block size and SHA-256.
+    pub fn with_defaults(inner: F) -> Self {
+        Self::new(inner, DEFAULT_INTEGRITY_BLOCK_SIZE, HashAlgorithm::Sha256)
+    }
+}
+
+/// A [`Handle`] decorated with the shared integrity state for its file.
+pub struct VerifiedHandle<F: FileSystem> {
+    inner: Arc<F::Handle>,
+    fs: Arc<F>,
+    path: PathBuf,
+    state: Arc<Mutex<MerkleState>>,
+}
+
+impl<F: FileSystem> Handle for VerifiedHandle<F> {
+    fn truncate(&self, offset: usize) -> IoResult<()> {
+        self.inner.truncate(offset)?;
+        self.state.lock().unwrap().truncate_leaves(offset);
+        Ok(())
+    }
+
+    fn file_size(&self) -> IoResult<usize> {
+        self.inner.file_size()
+    }
+
+    fn sync(&self) -> IoResult<()> {
+        self.inner.sync()?;
+        let mut state = self.state.lock().unwrap();
+        if state.dirty {
+            state.rebuild();
+            store_merkle_state(&*self.fs, &self.path, &state)?;
+            state.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn lock_exclusive(&self) -> IoResult<()> {
+        self.inner.lock_exclusive()
+    }
+
+    fn try_lock_exclusive(&self) -> IoResult<()> {
+        self.inner.try_lock_exclusive()
+    }
+
+    fn unlock(&self) -> IoResult<()> {
+        self.inner.unlock()
+    }
+}
+
+/// A [`Read`] + [`Seek`] decorator that re-hashes each block it returns
+/// and rejects it with an [`InvalidData`](IoErrorKind::InvalidData) error
+/// if it doesn't match the leaf hash recorded for that block when it was
+/// written. Only the leaf hash is compared: the interior nodes and the
+/// root of the tree are not checked on read.
+pub struct VerifiedReader<F: FileSystem> {
+    inner: F::Reader,
+    path: PathBuf,
+    state: Arc<Mutex<MerkleState>>,
+    block_size: usize,
+    offset: usize,
+}
+
+impl<F: FileSystem> Read for VerifiedReader<F>
+where
+    F::Reader: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let block_index = self.offset / self.block_size;
+        let block_start = block_index * self.block_size;
+        let within = self.offset - block_start;
+
+        self.inner.seek(SeekFrom::Start(block_start as u64))?;
+        let mut block = vec![0u8; self.block_size];
+        let mut filled = 0;
+        while filled < block.len() {
+            let n = self.inner.read(&mut block[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        block.truncate(filled);
+        if block.len() <= within {
+            return Ok(0);
+        }
+
+        {
+            // Compare against `state.leaves` directly rather than
+            // `state.tree`: the tree is only rebuilt on `sync`, while
+            // `leaves` is kept current by every `VerifiedWriter::write`, so
+            // a block written but not yet synced would otherwise fail
+            // verification against a stale root.
+            let state = self.state.lock().unwrap();
+            if block_index >= state.leaves.len() {
+                return Err(integrity_error(&self.path, block_start as u64));
+            }
+            let leaf_hash = state.algorithm.hash(&[&block]);
+            if leaf_hash != state.leaves[block_index] {
+                return Err(integrity_error(&self.path, block_start as u64));
+            }
+        }
+
+        let available = &block[within..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl<F: FileSystem> Seek for VerifiedReader<F>
+where
+    F::Reader: Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new_offset = self.inner.seek(pos)?;
+        self.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+}
+
+/// A [`Write`] + [`Seek`] decorator that hashes each block as it's
+/// written, to be folded into the file's Merkle tree on the next
+/// [`VerifiedHandle::sync`]. Assumes sequential, append-only writes, which
+/// is how log files are written in this engine.
+pub struct VerifiedWriter<F: FileSystem> {
+    inner: F::Writer,
+    state: Arc<Mutex<MerkleState>>,
+    block_size: usize,
+    offset: usize,
+    pending: Vec<u8>,
+}
+
+impl<F: FileSystem> Write for VerifiedWriter<F>
+where
+    F::Writer: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let n = self.inner.write(buf)?;
+        self.pending.extend_from_slice(&buf[..n]);
+        while self.pending.len() >= self.block_size {
+            let block: Vec<u8> = self.pending.drain(..self.block_size).collect();
+            let index = self.offset / self.block_size;
+            self.state.lock().unwrap().record_block(index, &block);
+            self.offset += self.block_size;
+        }
+        if !self.pending.is_empty() {
+            // Record the trailing partial block on every write, not just on
+            // flush: nothing in the `Write`/`Handle` contract guarantees
+            // `flush` runs before `sync`, and a short final block must
+            // still show up in the tree it builds.
+            let index = self.offset / self.block_size;
+            self.state
+                .lock()
+                .unwrap()
+                .record_block(index, &self.pending);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+impl<F: FileSystem> Seek for VerifiedWriter<F>
+where
+    F::Writer: Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new_offset = self.inner.seek(pos)?;
+        self.offset = new_offset as usize;
+        Ok(new_offset)
+    }
+}
+
+impl<F: FileSystem> WriteExt for VerifiedWriter<F>
+where
+    F::Writer: Seek + WriteExt,
+{
+    fn truncate(&mut self, offset: usize) -> IoResult<()> {
+        self.inner.truncate(offset)?;
+        self.state.lock().unwrap().truncate_leaves(offset);
+        self.offset = offset;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn allocate(&mut self, offset: usize, size: usize, mode: FallocMode) -> IoResult<()> {
+        self.inner.allocate(offset, size, mode)
+    }
+}
+
+impl<F: FileSystem> FileSystem for VerifiedFileSystem<F>
+where
+    F::Reader: Read + Seek,
+    F::Writer: Write + Seek + WriteExt,
+{
+    type Handle = VerifiedHandle<F>;
+    type Reader = VerifiedReader<F>;
+    type Writer = VerifiedWriter<F>;
+
+    fn create<P: AsRef<Path>>(&self, path: P) -> IoResult<Self::Handle> {
+        let path = path.as_ref().to_path_buf();
+        let inner = self.inner.create(&path)?;
+        Ok(VerifiedHandle {
+            inner: Arc::new(inner),
+            fs: self.inner.clone(),
+            state: Arc::new(Mutex::new(MerkleState::empty(
+                self.block_size,
+                self.algorithm,
+            ))),
+            path,
+        })
+    }
+
+    fn open<P: AsRef<Path>>(&self, path: P, perm: Permission) -> IoResult<Self::Handle> {
+        let path = path.as_ref().to_path_buf();
+        let inner = self.inner.open(&path, perm)?;
+        let state = load_merkle_state(&*self.inner, &path, self.block_size, self.algorithm)?;
+        Ok(VerifiedHandle {
+            inner: Arc::new(inner),
+            fs: self.inner.clone(),
+            state: Arc::new(Mutex::new(state)),
+            path,
+        })
+    }
+
+    fn delete<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        self.inner.delete(path.as_ref())?;
+        let _ = self.inner.delete(sidecar_path(path.as_ref()));
+        Ok(())
+    }
+
+    fn rename<P: AsRef<Path>>(&self, src_path: P, dst_path: P) -> IoResult<()> {
+        self.inner.rename(src_path.as_ref(), dst_path.as_ref())?;
+        let _ = self.inner.rename(
+            sidecar_path(src_path.as_ref()),
+            sidecar_path(dst_path.as_ref()),
+        );
+        Ok(())
+    }
+
+    fn new_reader(&self, handle: Arc<Self::Handle>) -> IoResult<Self::Reader> {
+        Ok(VerifiedReader {
+            inner: self.inner.new_reader(handle.inner.clone())?,
+            path: handle.path.clone(),
+            state: handle.state.clone(),
+            block_size: self.block_size,
+            offset: 0,
+        })
+    }
+
+    fn new_writer(&self, handle: Arc<Self::Handle>) -> IoResult<Self::Writer> {
+        Ok(VerifiedWriter {
+            inner: self.inner.new_writer(handle.inner.clone())?,
+            state: handle.state.clone(),
+            block_size: self.block_size,
+            offset: 0,
+            pending: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::Builder;
+
+    fn temp_dir() -> tempfile::TempDir {
+        Builder::new()
+            .prefix("raft-engine-fallback-test")
+            .tempdir()
+            .unwrap()
+    }
+
+    #[test]
+    fn log_file_read_write_round_trip() {
+        let dir = temp_dir();
+        let fd = LogFd::create(dir.path().join("chunk0-1.log")).unwrap();
+        let mut file = LogFile::new(Arc::new(fd));
+
+        file.write_all(b"hello world").unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 11);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0u8; 11];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        // Reading past EOF returns a short read (0 bytes) rather than an
+        // error, exercising the break-on-n==0 path in `Read for LogFile`
+        // whose cursor bookkeeping previously lost partial progress.
+        let mut tail = [0u8; 4];
+        assert_eq!(file.read(&mut tail).unwrap(), 0);
+    }
+
+    #[test]
+    fn try_lock_exclusive_contends_across_handles_and_releases_on_drop() {
+        let dir = temp_dir();
+        let path = dir.path().join("chunk0-4.log");
+
+        let first = LogFd::create(&path).unwrap();
+        first.try_lock_exclusive().unwrap();
+
+        let second = LogFd::open(&path, Permission::ReadWrite).unwrap();
+        let err = second.try_lock_exclusive().unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::WouldBlock);
+
+        // Dropping `first` releases its flock: `Drop` unlocks it because
+        // `try_lock_exclusive` set its `locked` flag, and closing its
+        // descriptor would release the lock in any case.
+        drop(first);
+        second.try_lock_exclusive().unwrap();
+        second.unlock().unwrap();
+    }
+
+    #[test]
+    fn create_missing_parent_reports_path_and_preserves_error_kind() {
+        let dir = temp_dir();
+        let path = dir.path().join("missing-subdir").join("chunk0-3.log");
+        let err = LogFd::create(&path).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::NotFound);
+        let message = err.to_string();
+        assert!(message.contains("failed to create"));
+        assert!(message.contains(&format!("{:?}", path)));
+    }
+
+    #[test]
+    fn rename_missing_file_reports_both_paths() {
+        let dir = temp_dir();
+        let src = dir.path().join("missing-src.log");
+        let dst = dir.path().join("chunk0-3-dst.log");
+        let err = DefaultFileSystem.rename(&src, &dst).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::NotFound);
+        let message = err.to_string();
+        assert!(message.contains("failed to rename"));
+        assert!(message.contains(&format!("{:?}", src)));
+        assert!(message.contains(&format!("{:?}", dst)));
+    }
+
+    #[test]
+    fn allocate_extend_size_grows_file() {
+        let dir = temp_dir();
+        let fd = LogFd::create(dir.path().join("chunk0-2-extend.log")).unwrap();
+        fd.allocate(0, 4096, FallocMode::PreallocateExtendSize)
+            .unwrap();
+        assert_eq!(fd.size().unwrap(), 4096);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn allocate_keep_size_does_not_grow_file_on_native_path() {
+        let dir = temp_dir();
+        let fd = LogFd::create(dir.path().join("chunk0-2-keep.log")).unwrap();
+        fd.allocate(0, 4096, FallocMode::PreallocateKeepSize)
+            .unwrap();
+        assert_eq!(fd.size().unwrap(), 0);
+    }
+
+    #[test]
+    fn allocate_zero_range_zeroes_previously_written_data() {
+        let dir = temp_dir();
+        let fd = LogFd::create(dir.path().join("chunk0-2-zero.log")).unwrap();
+        fd.write_at(&[0xAAu8; 4096], 0).unwrap();
+        fd.allocate(0, 4096, FallocMode::ZeroRange).unwrap();
+        let mut buf = [0xFFu8; 4096];
+        fd.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    fn leaves(blocks: &[&[u8]]) -> Vec<Vec<u8>> {
+        blocks
+            .iter()
+            .map(|b| HashAlgorithm::Sha256.hash(&[b]))
+            .collect()
+    }
+
+    fn assert_all_verify(tree: &MerkleTree) {
+        for i in 0..tree.leaf_count() {
+            let leaf_hash = tree.digest_at(0, i).to_vec();
+            let proof = tree.proof(i);
+            assert!(
+                MerkleTree::verify(tree.algorithm, &leaf_hash, i, &proof, tree.root()),
+                "leaf {i} failed to verify against the root"
+            );
+        }
+    }
+
+    #[test]
+    fn merkle_tree_empty() {
+        let tree = MerkleTree::build(&[], HashAlgorithm::Sha256);
+        assert_eq!(tree.leaf_count(), 1);
+        assert_eq!(tree.root(), HashAlgorithm::Sha256.hash(&[&[]]).as_slice());
+        assert_all_verify(&tree);
+    }
+
+    #[test]
+    fn merkle_tree_single_leaf() {
+        let tree = MerkleTree::build(&leaves(&[b"one block"]), HashAlgorithm::Sha256);
+        assert_eq!(tree.leaf_count(), 1);
+        assert_eq!(tree.root(), tree.digest_at(0, 0));
+        assert_all_verify(&tree);
+    }
+
+    #[test]
+    fn merkle_tree_even_leaf_count() {
+        let tree = MerkleTree::build(&leaves(&[b"a", b"b", b"c", b"d"]), HashAlgorithm::Sha256);
+        assert_eq!(tree.leaf_count(), 4);
+        assert_all_verify(&tree);
+    }
+
+    #[test]
+    fn merkle_tree_odd_leaf_count() {
+        let tree = MerkleTree::build(&leaves(&[b"a", b"b", b"c"]), HashAlgorithm::Sha256);
+        assert_eq!(tree.leaf_count(), 3);
+        assert_all_verify(&tree);
+    }
+
+    #[test]
+    fn merkle_tree_verify_rejects_wrong_leaf() {
+        let tree = MerkleTree::build(&leaves(&[b"a", b"b", b"c", b"d"]), HashAlgorithm::Sha256);
+        let proof = tree.proof(1);
+        let wrong_hash = HashAlgorithm::Sha256.hash(&[b"not b"]);
+        assert!(!MerkleTree::verify(
+            tree.algorithm,
+            &wrong_hash,
+            1,
+            &proof,
+            tree.root()
+        ));
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let tree = MerkleTree::build(&leaves(&[b"a", b"b", b"c"]), HashAlgorithm::Sha256);
+        let encoded = encode_sidecar(4096, &tree);
+        let decoded = decode_sidecar(
+            &encoded,
+            Path::new("/tmp/x.log"),
+            4096,
+            HashAlgorithm::Sha256,
+        )
+        .expect("sidecar should decode");
+        assert_eq!(decoded.root(), tree.root());
+        assert_eq!(decoded.leaf_count(), tree.leaf_count());
+        for i in 0..tree.leaf_count() {
+            assert_eq!(decoded.digest_at(0, i), tree.digest_at(0, i));
+        }
+    }
+
+    #[test]
+    fn sidecar_round_trip_empty() {
+        let tree = MerkleTree::build(&[], HashAlgorithm::Sha256);
+        let encoded = encode_sidecar(4096, &tree);
+        let decoded = decode_sidecar(
+            &encoded,
+            Path::new("/tmp/x.log"),
+            4096,
+            HashAlgorithm::Sha256,
+        )
+        .expect("sidecar should decode");
+        assert_eq!(decoded.root(), tree.root());
+    }
+
+    #[test]
+    fn sidecar_rejects_mismatched_block_size() {
+        let tree = MerkleTree::build(&leaves(&[b"a"]), HashAlgorithm::Sha256);
+        let encoded = encode_sidecar(4096, &tree);
+        let err = decode_sidecar(
+            &encoded,
+            Path::new("/tmp/x.log"),
+            8192,
+            HashAlgorithm::Sha256,
+        )
+        .expect_err("block size mismatch should be rejected");
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sidecar_rejects_truncated_data() {
+        let tree = MerkleTree::build(&leaves(&[b"a", b"b", b"c"]), HashAlgorithm::Sha256);
+        let mut encoded = encode_sidecar(4096, &tree);
+        encoded.truncate(encoded.len() - 1);
+        let err = decode_sidecar(
+            &encoded,
+            Path::new("/tmp/x.log"),
+            4096,
+            HashAlgorithm::Sha256,
+        )
+        .expect_err("truncated sidecar should be rejected");
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+    }
+}